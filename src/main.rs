@@ -3,7 +3,7 @@ use std::{
     mem,
 };
 
-use anyhow::{ensure, Result};
+use anyhow::Result;
 
 fn main() -> Result<()> {
     let s = "(first (list 1 (+ 2 3) 9))";
@@ -12,110 +12,475 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// A half-open byte range `[start, end)` into the original source text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug)]
 pub enum Expr {
-    Group(Vec<Expr>),
-    Atom(Atom),
+    Group(Vec<Expr>, Span),
+    Atom(Atom, Span),
 }
 
 #[derive(Debug)]
 pub enum Atom {
     Ident(String),
-    Num(u32),
+    Str(String),
+    Float(f64),
+    Int(i64),
+    Bool(bool),
+    Nil,
 }
 
 pub fn parse(expr: &str) -> Result<Expr> {
     let tokens = tokenize(expr);
-    parse_tokens(tokens)
+    Ok(parse_tokens(tokens)?)
+}
+
+/// Errors produced while parsing. All but [`ParseError::WrongTopLevelCount`]
+/// point at the offending byte span(s) so callers can render a pointed
+/// diagnostic with [`render_diagnostic`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// A `)` with no matching open paren.
+    UnexpectedRparen(Span),
+    /// EOF reached with these `(`s still open (outermost first).
+    UnclosedLparens(Vec<Span>),
+    /// The dummy top-level context didn't hold exactly one expression.
+    WrongTopLevelCount(usize),
+    /// A `"` with no matching closing `"` before EOF.
+    UnterminatedString(Span),
+    /// A reader-macro prefix (`'`, `` ` ``, `,`, `,@`) with no expression
+    /// after it for it to wrap.
+    DanglingPrefix(Span),
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedRparen(span) => {
+                write!(f, "unexpected closing paren at byte {}", span.start)
+            }
+            ParseError::UnclosedLparens(spans) => {
+                write!(f, "{} unclosed open paren(s)", spans.len())
+            }
+            ParseError::WrongTopLevelCount(found) => {
+                write!(f, "expected 1 top-level expression, found {found}")
+            }
+            ParseError::UnterminatedString(span) => {
+                write!(f, "unterminated string literal starting at byte {}", span.start)
+            }
+            ParseError::DanglingPrefix(span) => {
+                write!(f, "reader-macro prefix at byte {} has no following expression", span.start)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Renders `error` as a caret-underlined snippet of `source`, in the style
+/// of a compiler diagnostic. [`ParseError::WrongTopLevelCount`] has no span
+/// and is rendered as its plain message.
+pub fn render_diagnostic(source: &str, error: &ParseError) -> String {
+    match error {
+        ParseError::UnexpectedRparen(span) => {
+            render_spans(source, &[(*span, "unexpected closing paren")])
+        }
+        ParseError::UnclosedLparens(spans) => {
+            let mut labeled: Vec<_> = spans.iter().map(|s| (*s, "unclosed open paren")).collect();
+            // These are only ever reported once input has run out, so the
+            // matching `)` was expected right at EOF.
+            let eof = Span { start: source.len(), end: source.len() };
+            labeled.push((eof, "expected a matching `)` here"));
+            render_spans(source, &labeled)
+        }
+        ParseError::WrongTopLevelCount(_) => error.to_string(),
+        ParseError::UnterminatedString(span) => {
+            render_spans(source, &[(*span, "unterminated string literal")])
+        }
+        ParseError::DanglingPrefix(span) => {
+            render_spans(source, &[(*span, "prefix has no following expression")])
+        }
+    }
+}
+
+fn render_spans(source: &str, spans: &[(Span, &str)]) -> String {
+    let mut out = String::new();
+    for (i, (span, label)) in spans.iter().enumerate() {
+        if i != 0 {
+            out.push('\n');
+        }
+        let (line, col) = locate(source, span.start);
+        out.push_str(line);
+        out.push('\n');
+        let underline_len = (span.end - span.start).max(1);
+        out.push_str(&" ".repeat(col));
+        out.push_str(&"^".repeat(underline_len));
+        out.push(' ');
+        out.push_str(label);
+    }
+    out
+}
+
+/// The source line containing byte `offset`, and `offset`'s column within it.
+fn locate(source: &str, offset: usize) -> (&str, usize) {
+    let mut start = 0;
+    for line in source.split('\n') {
+        let end = start + line.len();
+        if offset <= end {
+            return (line, offset - start);
+        }
+        start = end + 1;
+    }
+    (source, offset)
 }
 
 enum Token {
-    Lparen,
-    Rparen,
-    Atom(Atom),
+    Lparen(Span),
+    Rparen(Span),
+    Atom(Atom, Span),
+    UnterminatedString(Span),
+    Prefix(Prefix, Span),
+}
+
+/// A reader-macro prefix that wraps the next expression in a two-element
+/// `(<name> expr)` group, e.g. `'x` desugars to `(quote x)`.
+#[derive(Debug, Clone, Copy)]
+enum Prefix {
+    Quote,
+    Quasiquote,
+    Unquote,
+    UnquoteSplicing,
 }
 
-fn tokenize(expr: &str) -> impl Iterator<Item = Token> + '_ {
+impl Prefix {
+    fn ident(self) -> &'static str {
+        match self {
+            Prefix::Quote => "quote",
+            Prefix::Quasiquote => "quasiquote",
+            Prefix::Unquote => "unquote",
+            Prefix::UnquoteSplicing => "unquote-splicing",
+        }
+    }
+}
+
+fn tokenize(expr: &str) -> impl Iterator<Item = Token> {
+    let mut tokens = vec![];
     let mut curr_token = String::new();
+    let mut curr_start = 0;
+    let mut chars = expr.char_indices().peekable();
 
-    expr.chars().chain([' ']).flat_map(move |c| {
-        let atom = if matches!(c, '(' | ')' | ' ') && !curr_token.is_empty() {
-            let atom = Atom::new(mem::take(&mut curr_token));
-            Some(Token::Atom(atom))
-        } else {
-            None
-        };
+    while let Some((i, c)) = chars.next() {
+        // `;` starts a line comment: skip everything up to (and including) the newline.
+        if c == ';' {
+            flush_atom(&mut tokens, &mut curr_token, curr_start, i);
+            for (_, c) in chars.by_ref() {
+                if c == '\n' {
+                    break;
+                }
+            }
+            continue;
+        }
+
+        if c == '"' {
+            flush_atom(&mut tokens, &mut curr_token, curr_start, i);
+            let start = i;
+            let mut s = String::new();
+            let mut end = None;
+
+            while let Some((j, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        end = Some(j + 1);
+                        break;
+                    }
+                    '\\' => match chars.next() {
+                        Some((_, 'n')) => s.push('\n'),
+                        Some((_, 't')) => s.push('\t'),
+                        Some((_, '\\')) => s.push('\\'),
+                        Some((_, '"')) => s.push('"'),
+                        Some((_, other)) => s.push(other),
+                        None => break,
+                    },
+                    _ => s.push(c),
+                }
+            }
 
-        let paren = match c {
-            '(' => Some(Token::Lparen),
-            ')' => Some(Token::Rparen),
-            ' ' => None,
-            _ => {
-                curr_token.push(c);
-                None
+            match end {
+                Some(end) => tokens.push(Token::Atom(Atom::Str(s), Span { start, end })),
+                None => tokens.push(Token::UnterminatedString(Span { start, end: expr.len() })),
             }
-        };
+            continue;
+        }
+
+        if matches!(c, '\'' | '`' | ',') {
+            flush_atom(&mut tokens, &mut curr_token, curr_start, i);
+            let (prefix, end) = if c == ',' && matches!(chars.peek(), Some((_, '@'))) {
+                let (j, _) = chars.next().unwrap();
+                (Prefix::UnquoteSplicing, j + 1)
+            } else {
+                let prefix = match c {
+                    '\'' => Prefix::Quote,
+                    '`' => Prefix::Quasiquote,
+                    _ => Prefix::Unquote,
+                };
+                (prefix, i + 1)
+            };
+            tokens.push(Token::Prefix(prefix, Span { start: i, end }));
+            continue;
+        }
+
+        if matches!(c, '(' | ')' | ' ' | '\n' | '\t' | '\r') {
+            flush_atom(&mut tokens, &mut curr_token, curr_start, i);
+        } else {
+            if curr_token.is_empty() {
+                curr_start = i;
+            }
+            curr_token.push(c);
+            continue;
+        }
+
+        match c {
+            '(' => tokens.push(Token::Lparen(Span { start: i, end: i + 1 })),
+            ')' => tokens.push(Token::Rparen(Span { start: i, end: i + 1 })),
+            _ => {}
+        }
+    }
+
+    flush_atom(&mut tokens, &mut curr_token, curr_start, expr.len());
 
-        [atom, paren].into_iter().flatten()
-    })
+    tokens.into_iter()
+}
+
+fn flush_atom(tokens: &mut Vec<Token>, curr_token: &mut String, start: usize, end: usize) {
+    if !curr_token.is_empty() {
+        let atom = Atom::new(mem::take(curr_token));
+        tokens.push(Token::Atom(atom, Span { start, end }));
+    }
 }
 
 impl Atom {
     fn new(s: String) -> Self {
-        match s.parse() {
-            Ok(n) => Self::Num(n),
-            Err(_) => Self::Ident(s),
+        match s.as_str() {
+            "nil" => return Self::Nil,
+            "#t" | "true" => return Self::Bool(true),
+            "#f" | "false" => return Self::Bool(false),
+            _ => {}
+        }
+
+        if let Ok(i) = s.parse::<i64>() {
+            return Self::Int(i);
+        }
+        // Require a digit so identifiers like `nan`/`inf`/`infinity` (which
+        // `f64::from_str` happily accepts) stay `Ident` instead of becoming
+        // an untextual `Float(NaN)` / `Float(inf)`.
+        if s.bytes().any(|b| b.is_ascii_digit())
+            && let Ok(f) = s.parse::<f64>()
+        {
+            return Self::Float(f);
+        }
+
+        Self::Ident(s)
+    }
+}
+
+/// One open-paren-delimited context of sibling expressions being accumulated.
+struct Context {
+    exprs: Vec<Expr>,
+    /// Span of the `(` that opened this context; `None` for the top-level
+    /// "dummy" context, which collects any number of top-level expressions.
+    open_span: Option<Span>,
+    /// Reader-macro prefixes that applied to this context's `(` itself
+    /// (e.g. the `'` in `'(a b)`), captured from the shared `pending` stack
+    /// when the context was opened. Applied to the whole `Group` once this
+    /// context closes, instead of to its first child.
+    pending: Vec<(Prefix, Span)>,
+}
+
+fn expr_span(expr: &Expr) -> Span {
+    match expr {
+        Expr::Group(_, span) | Expr::Atom(_, span) => *span,
+    }
+}
+
+// Wraps `expr` with any `pending` reader-macro prefixes (innermost, i.e.
+// most-recently-seen, first) and appends the result to the current context.
+fn push_expr(contexts: &mut [Context], pending: &mut Vec<(Prefix, Span)>, mut expr: Expr) {
+    while let Some((prefix, span)) = pending.pop() {
+        let wrapper_span = Span { start: span.start, end: expr_span(&expr).end };
+        let name = Expr::Atom(Atom::Ident(prefix.ident().to_string()), span);
+        expr = Expr::Group(vec![name, expr], wrapper_span);
+    }
+    contexts.last_mut().unwrap().exprs.push(expr);
+}
+
+// Enter a new context, "close" the current one, or append an atom.
+// It becomes a single `group`, appended to the context that contains it.
+//
+// Returns `Err` for a stray `)` or an unterminated string, leaving it to the caller to decide whether
+// that's fatal (as in [`parse_tokens`]) or just a diagnostic to record and
+// move past (as in [`parse_recovering`]).
+fn apply_token(
+    contexts: &mut Vec<Context>,
+    pending: &mut Vec<(Prefix, Span)>,
+    token: Token,
+) -> Result<(), ParseError> {
+    match token {
+        // The prefixes seen so far apply to this whole group once it
+        // closes, not to whatever's pushed first inside it, so they move
+        // into the new context rather than staying on the shared stack.
+        Token::Lparen(span) => {
+            let captured = mem::take(pending);
+            contexts.push(Context { exprs: vec![], open_span: Some(span), pending: captured });
         }
+        Token::Rparen(span) => {
+            // You can't "close" the dummy context.
+            if contexts.len() < 2 {
+                return Err(ParseError::UnexpectedRparen(span));
+            }
+
+            let mut ctx = contexts.pop().unwrap();
+            let group_span = Span { start: ctx.open_span.unwrap().start, end: span.end };
+            push_expr(contexts, &mut ctx.pending, Expr::Group(ctx.exprs, group_span));
+        }
+        Token::Atom(atom, span) => push_expr(contexts, pending, Expr::Atom(atom, span)),
+        Token::UnterminatedString(span) => return Err(ParseError::UnterminatedString(span)),
+        Token::Prefix(prefix, span) => pending.push((prefix, span)),
     }
+    Ok(())
 }
 
-fn parse_tokens(tokens: impl Iterator<Item = Token>) -> Result<Expr> {
-    // We include a "dummy" outer-most context, to collect up any number of
-    // top-level expressions.
-    //
+// Drives `contexts` to completion, returning every top-level expression
+// found (the contents of the dummy context).
+fn parse_all(tokens: impl Iterator<Item = Token>) -> Result<Vec<Expr>, ParseError> {
     // invariant: `contexts.len() >= 1`.
-    let dummy_context = vec![];
-    let mut contexts: Vec<Vec<Expr>> = vec![dummy_context];
+    let mut contexts = vec![Context { exprs: vec![], open_span: None, pending: vec![] }];
+    let mut pending = vec![];
 
     for t in tokens {
-        match t {
-            // Enter a new context.
-            Token::Lparen => contexts.push(vec![]),
-            // "Close" the current context.
-            // It becomes a single `group`, appended to the context that contains it.
-            Token::Rparen => {
-                // You can't "close" the dummy context.
-                ensure!(contexts.len() >= 2, "unexpected closing paren");
-
-                let group = contexts.pop().unwrap();
-                contexts.last_mut().unwrap().push(Expr::Group(group));
-            }
-            // Append an atom to the current context.
-            Token::Atom(atom) => contexts.last_mut().unwrap().push(Expr::Atom(atom)),
+        apply_token(&mut contexts, &mut pending, t)?;
+    }
+
+    if let Some(&(_, span)) = pending.first() {
+        return Err(ParseError::DanglingPrefix(span));
+    }
+
+    if contexts.len() > 1 {
+        let spans = contexts[1..].iter().map(|c| c.open_span.unwrap()).collect();
+        return Err(ParseError::UnclosedLparens(spans));
+    }
+
+    Ok(contexts.pop().unwrap().exprs)
+}
+
+fn parse_tokens(tokens: impl Iterator<Item = Token>) -> Result<Expr, ParseError> {
+    let mut exprs = parse_all(tokens)?;
+
+    if exprs.len() != 1 {
+        return Err(ParseError::WrongTopLevelCount(exprs.len()));
+    }
+
+    Ok(exprs.pop().unwrap())
+}
+
+/// Parses a whole program: any number of top-level forms, e.g. the
+/// contents of a `.lisp` file. See [`parse`] to parse a single expression.
+pub fn parse_program(source: &str) -> Result<Vec<Expr>> {
+    Ok(parse_all(tokenize(source))?)
+}
+
+/// Like [`parse`], but never stops at the first problem: a stray `)` is
+/// dropped and recorded as a diagnostic, and at EOF any still-open `(`s are
+/// synthetically closed (innermost first) so the caller still gets a usable
+/// partial tree, alongside every diagnostic found in one pass.
+pub fn parse_recovering(source: &str) -> (Option<Expr>, Vec<ParseError>) {
+    let mut contexts = vec![Context { exprs: vec![], open_span: None, pending: vec![] }];
+    let mut pending = vec![];
+    let mut errors = vec![];
+
+    for t in tokenize(source) {
+        if let Err(e) = apply_token(&mut contexts, &mut pending, t) {
+            errors.push(e);
         }
     }
 
-    ensure!(
-        contexts.len() == 1,
-        "{} unclosed open paren(s)",
-        contexts.len() - 1,
-    );
-    let mut dummy_context = contexts.pop().unwrap();
+    // A prefix still pending here has nothing left to wrap: it trails off
+    // at EOF with no following expression.
+    for (_, span) in pending.drain(..) {
+        errors.push(ParseError::DanglingPrefix(span));
+    }
+
+    while contexts.len() > 1 {
+        let mut ctx = contexts.pop().unwrap();
+        let open_span = ctx.open_span.unwrap();
+        errors.push(ParseError::UnclosedLparens(vec![open_span]));
+        push_expr(&mut contexts, &mut ctx.pending, Expr::Group(ctx.exprs, open_span));
+    }
 
-    ensure!(
-        dummy_context.len() == 1,
-        "expected 1 top-level expression, found {}",
-        dummy_context.len(),
-    );
-    let expr = dummy_context.pop().unwrap();
+    let mut dummy_context = contexts.pop().unwrap().exprs;
+    let expr = match dummy_context.len() {
+        0 => None,
+        1 => dummy_context.pop(),
+        found => {
+            errors.push(ParseError::WrongTopLevelCount(found));
+            Some(Expr::Group(dummy_context, Span { start: 0, end: source.len() }))
+        }
+    };
 
-    Ok(expr)
+    (expr, errors)
+}
+
+impl Expr {
+    /// Renders `self` like [`Display`], except that a group which wouldn't
+    /// fit within `width` columns is broken across multiple lines, one child
+    /// per line, indented by nesting depth. Groups that fit inline (and all
+    /// atoms) are printed exactly as by `Display`.
+    pub fn pretty(&self, width: usize) -> String {
+        let mut out = String::new();
+        self.pretty_into(&mut out, width, 0);
+        out
+    }
+
+    fn pretty_into(&self, out: &mut String, width: usize, indent: usize) {
+        const SHIFT: usize = 2;
+
+        let sub_exprs = match self {
+            Expr::Atom(..) => {
+                out.push_str(&self.to_string());
+                return;
+            }
+            Expr::Group(sub_exprs, _) => sub_exprs,
+        };
+
+        let inline = self.to_string();
+        if sub_exprs.is_empty() || indent + inline.len() <= width {
+            out.push_str(&inline);
+            return;
+        }
+
+        out.push('(');
+        sub_exprs[0].pretty_into(out, width, indent + 1);
+        for child in &sub_exprs[1..] {
+            out.push('\n');
+            out.push_str(&" ".repeat(indent + SHIFT));
+            child.pretty_into(out, width, indent + SHIFT);
+        }
+        out.push(')');
+    }
 }
 
 impl Display for Expr {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Expr::Group(sub_exprs) => {
+            Expr::Group(sub_exprs, _) => {
+                if let Some(prefix) = sugar_prefix(sub_exprs) {
+                    return write!(f, "{prefix}{}", sub_exprs[1]);
+                }
+
                 write!(f, "(")?;
                 for (i, expr) in sub_exprs.iter().enumerate() {
                     if i != 0 {
@@ -127,16 +492,128 @@ impl Display for Expr {
                 write!(f, ")")?;
                 Ok(())
             }
-            Expr::Atom(atom) => write!(f, "{atom}"),
+            Expr::Atom(atom, _) => write!(f, "{atom}"),
         }
     }
 }
 
+// If `sub_exprs` is a two-element reader-macro group like `(quote x)`,
+// returns the sugared prefix (`'`, `` ` ``, `,`, or `,@`) to print before `x`.
+fn sugar_prefix(sub_exprs: &[Expr]) -> Option<&'static str> {
+    match sub_exprs {
+        [Expr::Atom(Atom::Ident(head), _), _] => match head.as_str() {
+            "quote" => Some("'"),
+            "quasiquote" => Some("`"),
+            "unquote" => Some(","),
+            "unquote-splicing" => Some(",@"),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 impl Display for Atom {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
             Atom::Ident(name) => write!(f, "{name}"),
-            Atom::Num(x) => write!(f, "{x}"),
+            Atom::Str(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '"' => write!(f, "\\\"")?,
+                        _ => write!(f, "{c}")?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            // Force a decimal point so e.g. `1.0` round-trips, instead of
+            // printing as the integer-looking `1`.
+            Atom::Float(x) if x.fract() == 0.0 && x.is_finite() => write!(f, "{x:.1}"),
+            Atom::Float(x) => write!(f, "{x}"),
+            Atom::Int(x) => write!(f, "{x}"),
+            Atom::Bool(true) => write!(f, "#t"),
+            Atom::Bool(false) => write!(f, "#f"),
+            Atom::Nil => write!(f, "nil"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(s: &str) {
+        assert_eq!(parse(s).unwrap().to_string(), s);
+    }
+
+    #[test]
+    fn quote_prefix_wraps_whole_group() {
+        roundtrip("'(a b)");
+        roundtrip("'('a b)");
+        roundtrip(",@'x");
+    }
+
+    #[test]
+    fn all_prefixes_roundtrip() {
+        roundtrip("'x");
+        roundtrip("`x");
+        roundtrip(",x");
+        roundtrip(",@x");
+    }
+
+    #[test]
+    fn literal_roundtrips() {
+        roundtrip(r#""hello\nworld""#);
+        roundtrip("1.5");
+        roundtrip("-3");
+        roundtrip("#t");
+        roundtrip("#f");
+        roundtrip("nil");
+    }
+
+    #[test]
+    fn nan_and_inf_stay_identifiers() {
+        assert!(matches!(parse("nan").unwrap(), Expr::Atom(Atom::Ident(s), _) if s == "nan"));
+        assert!(matches!(parse("inf").unwrap(), Expr::Atom(Atom::Ident(s), _) if s == "inf"));
+    }
+
+    #[test]
+    fn program_parses_multiple_forms_with_comments() {
+        let exprs = parse_program("a ; first\nb (c d)").unwrap();
+        let rendered: Vec<_> = exprs.iter().map(ToString::to_string).collect();
+        assert_eq!(rendered, vec!["a", "b", "(c d)"]);
+    }
+
+    #[test]
+    fn recovering_reports_unexpected_rparen_and_keeps_going() {
+        let (expr, errors) = parse_recovering("(a))");
+        assert_eq!(expr.unwrap().to_string(), "(a)");
+        assert!(matches!(errors[..], [ParseError::UnexpectedRparen(_)]));
+    }
+
+    #[test]
+    fn recovering_closes_dangling_parens() {
+        let (expr, errors) = parse_recovering("(a (b");
+        assert_eq!(expr.unwrap().to_string(), "(a (b))");
+        assert!(matches!(errors[..], [ParseError::UnclosedLparens(_), ParseError::UnclosedLparens(_)]));
+    }
+
+    #[test]
+    fn recovering_reports_dangling_prefix() {
+        let (expr, errors) = parse_recovering("'");
+        assert!(expr.is_none());
+        assert!(matches!(errors[..], [ParseError::DanglingPrefix(_)]));
+    }
+
+    #[test]
+    fn pretty_printed_output_reparses_to_the_same_tree() {
+        let source = "(first (list 1 (+ 2 3) 9))";
+        let expr = parse(source).unwrap();
+        let pretty = expr.pretty(10);
+        assert_ne!(pretty, source);
+        assert_eq!(parse(&pretty).unwrap().to_string(), expr.to_string());
+    }
+}